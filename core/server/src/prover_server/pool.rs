@@ -1,11 +1,13 @@
 // Built-in
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Arc;
 use std::{thread, time};
 // External
 use crate::franklin_crypto::bellman::pairing::ff::PrimeField;
 use futures::channel::mpsc;
-use log::info;
+use log::{error, info, warn};
+use parking_lot::RwLock;
 // Workspace deps
 use circuit::witness::change_pubkey_offchain::{
     apply_change_pubkey_offchain_tx, calculate_change_pubkey_offchain_from_witness,
@@ -33,31 +35,354 @@ use models::node::{Fr, FranklinOp};
 use plasma::state::CollectedFee;
 use prover::prover_data::ProverData;
 
+/// Error raised while maintaining the prover data pool.
+///
+/// The variants distinguish conditions that are expected to clear up on their
+/// own (a transient DB hiccup, a block that hasn't been loaded yet) from
+/// invariant violations that indicate a logic bug or corrupted data.
+/// `Maintainer::maintain` logs the former as a warning and the latter as an
+/// error; in both cases it keeps running and starts the next round.
+#[derive(Debug)]
+pub enum MaintainerError {
+    /// Failed to connect to the database or the query itself failed.
+    Database(String),
+    /// Expected data (e.g. a queued commit operation, or a state snapshot to
+    /// revert to) is not present; the caller should retry once it becomes
+    /// available.
+    Inconsistent(String),
+    /// An invariant was violated (e.g. a computed root not matching the
+    /// committed one). This is not expected to resolve on retry.
+    Fatal(String),
+    /// The pool lock was not acquired within the configured deadline,
+    /// e.g. because a stuck consumer is holding it on the other side of
+    /// the `Arc<RwLock<ProversDataPool>>`.
+    LockTimeout(String),
+}
+
+impl MaintainerError {
+    /// Returns `true` if the condition is expected to be transient, i.e.
+    /// retrying the failed operation later has a chance of succeeding.
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            MaintainerError::Database(_)
+            | MaintainerError::Inconsistent(_)
+            | MaintainerError::LockTimeout(_) => true,
+            MaintainerError::Fatal(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for MaintainerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MaintainerError::Database(msg) => write!(f, "database error: {}", msg),
+            MaintainerError::Inconsistent(msg) => write!(f, "inconsistent state: {}", msg),
+            MaintainerError::Fatal(msg) => write!(f, "fatal error: {}", msg),
+            MaintainerError::LockTimeout(msg) => write!(f, "lock timeout: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for MaintainerError {}
+
+/// Maximum number of past `account_state` snapshots kept for `revert_to`.
+///
+/// Bounds the memory cost of reorg recovery; a reorg deeper than this many
+/// blocks falls back to reloading committed state from scratch.
+const MAX_ACCOUNT_STATE_SNAPSHOTS: usize = 16;
+
+/// Checks that `old_root` descends from `expected` (the `new_root` of the
+/// previously stored block), if there is one to compare against. Pulled out
+/// of `ProversDataPool::store` so the chain-verification rule itself can be
+/// unit-tested without constructing a full `ProverData`.
+fn check_root_chain(expected: Option<&Fr>, old_root: &Fr, block: i64) -> Result<(), MaintainerError> {
+    if let Some(expected) = expected {
+        if old_root != expected {
+            return Err(MaintainerError::Fatal(format!(
+                "witness chain broken at block {}: old_root does not match the new_root \
+                 of the previously prepared block",
+                block
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// zstd-compresses `bytes` at `level`. Thin wrapper kept separate from
+/// `ProversDataPool::store` so the round trip can be unit-tested directly.
+fn compress(bytes: &[u8], level: i32) -> std::io::Result<Vec<u8>> {
+    zstd::encode_all(bytes, level)
+}
+
+/// Inverse of `compress`.
+fn decompress(bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    zstd::decode_all(bytes)
+}
+
+/// Pushes `(block, state)` onto `history`, dropping the oldest entry once
+/// `MAX_ACCOUNT_STATE_SNAPSHOTS` is exceeded. Pulled out of
+/// `Maintainer::push_snapshot` so the bound can be unit-tested without
+/// constructing a `Maintainer`.
+fn push_account_state_snapshot(
+    history: &mut VecDeque<(u32, AccountMap)>,
+    block: u32,
+    state: AccountMap,
+) {
+    history.push_back((block, state));
+    while history.len() > MAX_ACCOUNT_STATE_SNAPSHOTS {
+        history.pop_front();
+    }
+}
+
+/// Pops snapshots off `history`, restoring `account_state` to each one in
+/// turn, until `block` is reached exactly. Rebuilds `circuit_account_tree`
+/// from the restored state once found. Pulled out of `Maintainer::revert_to`
+/// so the restore logic can be unit-tested without constructing a
+/// `Maintainer`.
+///
+/// Returns an error (without consuming the whole stack) if no snapshot for
+/// `block` is present, e.g. because the reorg is deeper than
+/// `MAX_ACCOUNT_STATE_SNAPSHOTS`.
+fn revert_account_state(
+    history: &mut VecDeque<(u32, AccountMap)>,
+    account_state: &mut Option<(u32, AccountMap)>,
+    circuit_account_tree: &mut Option<CircuitAccountTree>,
+    block: u32,
+) -> Result<(), MaintainerError> {
+    if !history.iter().any(|(snapshot_block, _)| *snapshot_block == block) {
+        return Err(MaintainerError::Inconsistent(format!(
+            "no snapshot available to revert cached state to block {}",
+            block
+        )));
+    }
+    loop {
+        match history.pop_back() {
+            Some((snapshot_block, snapshot_state)) => {
+                *account_state = Some((snapshot_block, snapshot_state));
+                if snapshot_block == block {
+                    let mut account_tree =
+                        CircuitAccountTree::new(models::params::account_tree_depth() as u32);
+                    if let Some((_, ref state)) = account_state {
+                        for (&account_id, account) in state {
+                            account_tree.insert(account_id, CircuitAccount::from(account.clone()));
+                        }
+                    }
+                    *circuit_account_tree = Some(account_tree);
+                    return Ok(());
+                }
+            }
+            None => {
+                return Err(MaintainerError::Inconsistent(format!(
+                    "no snapshot available to revert cached state to block {}",
+                    block
+                )));
+            }
+        }
+    }
+}
+
+/// A `ProverData` entry as kept in `ProversDataPool::prepared`.
+///
+/// When the pool is built with a compression level (see
+/// `ProversDataPool::new`), entries are serialized and zstd-compressed at
+/// rest instead of being kept as a live `ProverData`, trading CPU at
+/// insert/`get` time for a much smaller memory footprint per in-flight block.
+enum StoredProverData {
+    Plain(ProverData),
+    Compressed {
+        bytes: Vec<u8>,
+        uncompressed_len: usize,
+    },
+}
+
 pub struct ProversDataPool {
     last_prepared: i64,
     last_loaded: i64,
     limit: i64,
     operations: HashMap<i64, models::Operation>,
-    prepared: HashMap<i64, ProverData>,
+    prepared: HashMap<i64, StoredProverData>,
+    /// zstd compression level applied to `prepared` entries; `None` (the
+    /// default) stores them uncompressed.
+    compression_level: Option<i32>,
+    /// Running total of compressed bytes currently held in `prepared`.
+    compressed_bytes_total: u64,
+    /// Running total of the same entries' serialized, uncompressed size;
+    /// compare against `compressed_bytes_total` to judge the achieved ratio
+    /// and tune `limit` against available memory.
+    uncompressed_bytes_total: u64,
+    /// `(old_root, new_root)` recorded for blocks stored in `prepared`
+    /// (entries past a realignment point, or all of them after
+    /// `forget_chain_tracking`, are dropped while the data stays in
+    /// `prepared`), used by `verify_chain` to confirm the witness pipeline
+    /// forms an unbroken old_root -> new_root chain.
+    roots: HashMap<i64, (Fr, Fr)>,
+    /// `new_root` of the most recently stored block; a freshly prepared
+    /// block is rejected if its `old_root` doesn't match this.
+    last_new_root: Option<Fr>,
 }
 
 impl ProversDataPool {
-    pub fn new() -> Self {
+    /// Creates a new pool. `compression_level` enables zstd compression of
+    /// stored `ProverData` at the given level (see the `zstd` crate docs for
+    /// the valid range); pass `None` to keep entries in memory uncompressed.
+    pub fn new(compression_level: Option<i32>) -> Self {
         ProversDataPool {
             last_prepared: 0,
             last_loaded: 0,
             limit: 10,
             operations: HashMap::new(),
             prepared: HashMap::new(),
+            compression_level,
+            compressed_bytes_total: 0,
+            uncompressed_bytes_total: 0,
+            roots: HashMap::new(),
+            last_new_root: None,
         }
     }
 
-    pub fn get(&self, block: i64) -> Option<&ProverData> {
-        self.prepared.get(&block)
+    /// Returns the prover data prepared for `block`, decompressing it first
+    /// if the pool was configured with a compression level.
+    ///
+    /// A corrupted cache entry (the compressed bytes failing to decompress
+    /// or deserialize) is reported as `MaintainerError::Fatal` rather than
+    /// panicking, consistent with chunk0-1: the caller can log it and move
+    /// on instead of taking the whole process down.
+    pub fn get(&self, block: i64) -> Result<Option<ProverData>, MaintainerError> {
+        let entry = match self.prepared.get(&block) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        match entry {
+            StoredProverData::Plain(data) => Ok(Some(data.clone())),
+            StoredProverData::Compressed { bytes, .. } => {
+                let serialized = decompress(bytes).map_err(|e| {
+                    MaintainerError::Fatal(format!(
+                        "failed to decompress cached prover data for block {}: {}",
+                        block, e
+                    ))
+                })?;
+                let data = bincode::deserialize(&serialized).map_err(|e| {
+                    MaintainerError::Fatal(format!(
+                        "failed to deserialize cached prover data for block {}: {}",
+                        block, e
+                    ))
+                })?;
+                Ok(Some(data))
+            }
+        }
     }
 
     pub fn clean_up(&mut self, block: i64) {
-        self.prepared.remove(&block);
+        if let Some(entry) = self.prepared.remove(&block) {
+            if let StoredProverData::Compressed {
+                bytes,
+                uncompressed_len,
+            } = entry
+            {
+                self.compressed_bytes_total -= bytes.len() as u64;
+                self.uncompressed_bytes_total -= uncompressed_len as u64;
+            }
+        }
+        self.roots.remove(&block);
+    }
+
+    /// Inserts a freshly prepared `ProverData`, compressing it at rest if
+    /// the pool was configured with a compression level.
+    ///
+    /// Before accepting the entry, verifies that its `old_root` descends
+    /// from the `new_root` of the last block stored, refusing to store it
+    /// (and reporting the break point) on mismatch. This catches a
+    /// corrupted or out-of-order witness pipeline before any proof is
+    /// generated from it.
+    fn store(&mut self, block: i64, data: ProverData) -> Result<(), MaintainerError> {
+        check_root_chain(self.last_new_root.as_ref(), &data.old_root, block)?;
+
+        let old_root = data.old_root.clone();
+        let new_root = data.new_root.clone();
+
+        let entry = match self.compression_level {
+            Some(level) => {
+                let serialized = bincode::serialize(&data).map_err(|e| {
+                    MaintainerError::Fatal(format!("failed to serialize prover data: {}", e))
+                })?;
+                let compressed = compress(&serialized, level).map_err(|e| {
+                    MaintainerError::Fatal(format!("failed to compress prover data: {}", e))
+                })?;
+                self.compressed_bytes_total += compressed.len() as u64;
+                self.uncompressed_bytes_total += serialized.len() as u64;
+                StoredProverData::Compressed {
+                    bytes: compressed,
+                    uncompressed_len: serialized.len(),
+                }
+            }
+            None => StoredProverData::Plain(data),
+        };
+        self.prepared.insert(block, entry);
+        self.roots.insert(block, (old_root, new_root.clone()));
+        self.last_new_root = Some(new_root);
+        Ok(())
+    }
+
+    /// Realigns the root chain tracking after the cached account state was
+    /// rolled back (or reloaded) to `account_state_block`: any recorded
+    /// root for a block past that point belongs to a chain that may no
+    /// longer be canonical, so `store`'s chain check must forget it rather
+    /// than comparing the next legitimate block against a stale tip.
+    ///
+    /// `last_new_root` becomes whatever `new_root` was recorded for
+    /// `account_state_block` itself (if we still have it), or `None` if we
+    /// don't, which simply skips the chain check for the very next `store`.
+    pub(crate) fn realign_chain_tracking(&mut self, account_state_block: i64) {
+        self.roots.retain(|&block, _| block <= account_state_block);
+        self.last_new_root = self
+            .roots
+            .get(&account_state_block)
+            .map(|(_, new_root)| new_root.clone());
+    }
+
+    /// Forgets all recorded root-chain data. Used when the account state
+    /// was reloaded from scratch after a reorg deeper than the snapshot
+    /// history: we no longer know which, if any, previously stored blocks
+    /// still belong to the canonical chain.
+    pub(crate) fn forget_chain_tracking(&mut self) {
+        self.roots.clear();
+        self.last_new_root = None;
+    }
+
+    /// Walks `[from_block, to_block]` and confirms the recorded witness
+    /// roots form an unbroken `old_root -> new_root` chain, i.e. each
+    /// block's `old_root` equals its predecessor's `new_root`.
+    ///
+    /// Only blocks still held in `prepared` (not yet cleaned up) can be
+    /// checked; a missing block in the range is reported as an error too
+    /// (`Inconsistent`, while a broken link is `Fatal`), since either means
+    /// the pipeline can't currently be verified end-to-end.
+    pub fn verify_chain(&self, from_block: i64, to_block: i64) -> Result<(), MaintainerError> {
+        let mut expected_old_root: Option<&Fr> = None;
+        for block in from_block..=to_block {
+            let (old_root, new_root) = self.roots.get(&block).ok_or_else(|| {
+                MaintainerError::Inconsistent(format!(
+                    "no witness chain data recorded for block {} (range {}..={})",
+                    block, from_block, to_block
+                ))
+            })?;
+
+            if let Some(expected) = expected_old_root {
+                if old_root != expected {
+                    return Err(MaintainerError::Fatal(format!(
+                        "witness chain broken at block {}: old_root does not match the \
+                         new_root of block {}",
+                        block,
+                        block - 1
+                    )));
+                }
+            }
+
+            expected_old_root = Some(new_root);
+        }
+
+        Ok(())
     }
 
     fn has_capacity(&self) -> bool {
@@ -74,7 +399,7 @@ impl ProversDataPool {
         self.operations.insert(block, op);
     }
 
-    fn take_next_to_prove(&mut self) -> Result<models::Operation, String> {
+    fn take_next_to_prove(&mut self) -> Result<models::Operation, MaintainerError> {
         let mut first_from_loaded = 0;
         for key in self.operations.keys() {
             if first_from_loaded == 0 || *key < first_from_loaded {
@@ -83,7 +408,7 @@ impl ProversDataPool {
         }
         match self.operations.remove(&first_from_loaded) {
             Some(v) => Ok(v),
-            None => Err("data is inconsistent".to_owned()),
+            None => Err(MaintainerError::Inconsistent("data is inconsistent".to_owned())),
         }
     }
 }
@@ -105,11 +430,31 @@ pub struct Maintainer {
     data: Arc<RwLock<ProversDataPool>>,
     /// Routine refresh interval.
     rounds_interval: time::Duration,
+    /// Deadline for acquiring `data`'s lock; if it isn't acquired in time
+    /// (e.g. a stuck consumer is holding it on the other side), the round
+    /// is abandoned with `MaintainerError::LockTimeout` instead of blocking
+    /// the preparation thread indefinitely.
+    lock_timeout: time::Duration,
     /// Cached account state.
     ///
     /// This field is initialized at the first iteration of `maintain`
     /// routine, and is updated by applying the state diff after that.
     account_state: Option<(u32, AccountMap)>,
+    /// Cached circuit account tree, kept in sync with `account_state`.
+    ///
+    /// Built from scratch whenever `account_state` is loaded from storage or
+    /// reverted to a snapshot, and otherwise updated incrementally: every
+    /// account touched by a state diff
+    /// is re-inserted (or removed) directly, so Merkle recomputation is
+    /// proportional to the number of changed accounts rather than the
+    /// size of the whole account set.
+    circuit_account_tree: Option<CircuitAccountTree>,
+    /// Bounded history of past `account_state` values, most recent last,
+    /// pushed each time a diff is applied. Used by `revert_to` to restore
+    /// the cache after a block reorg without reloading committed state
+    /// from scratch. Bounded by `MAX_ACCOUNT_STATE_SNAPSHOTS`, since nothing
+    /// in this crate currently observes block finality to prune it earlier.
+    state_snapshots: VecDeque<(u32, AccountMap)>,
 }
 
 impl Maintainer {
@@ -118,12 +463,16 @@ impl Maintainer {
         conn_pool: storage::ConnectionPool,
         data: Arc<RwLock<ProversDataPool>>,
         rounds_interval: time::Duration,
+        lock_timeout: time::Duration,
     ) -> Self {
         Self {
             conn_pool,
             data,
             rounds_interval,
+            lock_timeout,
             account_state: None,
+            circuit_account_tree: None,
+            state_snapshots: VecDeque::new(),
         }
     }
 
@@ -140,37 +489,70 @@ impl Maintainer {
 
     /// Updates the pool data in an infinite loop, awaiting `rounds_interval` time
     /// between updates.
+    ///
+    /// A recoverable error (e.g. a transient DB connection failure) is logged
+    /// and retried after `rounds_interval`, rather than crashing the thread.
+    /// A fatal error (an invariant violation on a single block) is logged as
+    /// well, but since the offending block has already been dequeued, it
+    /// does not block preparation of the remaining blocks.
     fn maintain(&mut self) {
         info!("preparing prover data routine started");
         loop {
-            if self.has_capacity() {
-                self.take_next_commits()
-                    .expect("failed to get next commit operations");
+            if let Err(err) = self.run_iteration() {
+                if err.is_recoverable() {
+                    warn!("prover data maintenance iteration failed, will retry: {}", err);
+                } else {
+                    error!("prover data maintenance iteration hit a fatal error: {}", err);
+                }
             }
-            self.prepare_next().expect("failed to prepare prover data");
             thread::sleep(self.rounds_interval);
         }
     }
 
-    fn has_capacity(&self) -> bool {
-        let data = self.data.read().expect("failed to acquire a lock");
-        data.has_capacity()
+    /// Runs a single round of the maintenance loop.
+    fn run_iteration(&mut self) -> Result<(), MaintainerError> {
+        if self.has_capacity()? {
+            self.take_next_commits()?;
+        }
+        self.prepare_next()
+    }
+
+    /// Acquires the pool's read lock, giving up with `LockTimeout` instead
+    /// of blocking forever if it isn't available within `lock_timeout`.
+    fn read_pool(&self) -> Result<parking_lot::RwLockReadGuard<'_, ProversDataPool>, MaintainerError> {
+        self.data.try_read_for(self.lock_timeout).ok_or_else(|| {
+            MaintainerError::LockTimeout("timed out waiting for the pool read lock".to_owned())
+        })
+    }
+
+    /// Write-lock counterpart of `read_pool`.
+    fn write_pool(&self) -> Result<parking_lot::RwLockWriteGuard<'_, ProversDataPool>, MaintainerError> {
+        self.data.try_write_for(self.lock_timeout).ok_or_else(|| {
+            MaintainerError::LockTimeout("timed out waiting for the pool write lock".to_owned())
+        })
     }
 
-    fn take_next_commits(&self) -> Result<(), String> {
+    fn has_capacity(&self) -> Result<bool, MaintainerError> {
+        let data = self.read_pool()?;
+        Ok(data.has_capacity())
+    }
+
+    fn take_next_commits(&self) -> Result<(), MaintainerError> {
         let ops = {
-            let data = self.data.read().expect("failed to acquire a lock");
+            let data = self.read_pool()?;
             let storage = self
                 .conn_pool
                 .access_storage()
-                .expect("failed to connect to db");
+                .map_err(|e| MaintainerError::Database(format!("failed to connect to db: {}", e)))?;
             storage
                 .load_unverified_commits_after_block(data.last_loaded, data.limit)
-                .map_err(|e| format!("failed to read commit operations: {}", e))?
+                .map_err(|e| {
+                    MaintainerError::Database(format!("failed to read commit operations: {}", e))
+                })?
         };
 
         if !ops.is_empty() {
-            let mut data = self.data.write().expect("failed to acquire a lock");
+            let mut data = self.write_pool()?;
             for op in ops.into_iter() {
                 (*data).store_to_prove(op)
             }
@@ -179,9 +561,9 @@ impl Maintainer {
         Ok(())
     }
 
-    fn prepare_next(&mut self) -> Result<(), String> {
+    fn prepare_next(&mut self) -> Result<(), MaintainerError> {
         let op = {
-            let mut data = self.data.write().expect("failed to acquire a lock");
+            let mut data = self.write_pool()?;
             if data.all_prepared() {
                 return Ok(());
             }
@@ -190,83 +572,157 @@ impl Maintainer {
         let storage = self
             .conn_pool
             .access_storage()
-            .expect("failed to connect to db");
+            .map_err(|e| MaintainerError::Database(format!("failed to connect to db: {}", e)))?;
         let pd = self.build_prover_data(&storage, &op)?;
-        let mut data = self.data.write().expect("failed to acquire a lock");
+        let mut data = self.write_pool()?;
+        (*data).store(op.block.block_number as i64, pd)?;
         (*data).last_prepared += 1;
-        (*data).prepared.insert(op.block.block_number as i64, pd);
         Ok(())
     }
 
     /// Updates stored account state, obtaining the state for the requested block.
     ///
     /// This method updates the stored version of state with a diff, or initializes
-    /// the state if it was not initialized yet.
+    /// the state if it was not initialized yet. The cached `circuit_account_tree`
+    /// is kept in sync: on a full (re)load it is built from scratch, and on every
+    /// forward diff only the touched accounts are re-inserted (or removed).
+    ///
+    /// If `new_block` is behind the cached block, a reorg is assumed and the
+    /// cache is realigned via `revert_to` instead of loading committed state
+    /// from zero (falling back to a full reload if `revert_to` fails, e.g.
+    /// because no snapshot covers that far back).
     fn update_account_state(
         &mut self,
         storage: &storage::StorageProcessor,
         new_block: u32,
-    ) -> Result<(), String> {
-        match self.account_state {
-            Some((block, ref state)) => {
+    ) -> Result<(), MaintainerError> {
+        let cached_block = self.account_state.as_ref().map(|(block, _)| *block);
+
+        match cached_block {
+            Some(block) if new_block < block => {
+                warn!(
+                    "cached account state ({}) is ahead of requested block {}, assuming a reorg",
+                    block, new_block
+                );
+                if self.revert_to(new_block).is_err() {
+                    warn!(
+                        "no snapshot covers block {}, reloading committed state from scratch",
+                        new_block
+                    );
+                    self.load_account_state(storage, new_block)?;
+                }
+                Ok(())
+            }
+            Some(block) => {
                 // State is initialized. We need to load diff (if any) and update
                 // the stored state.
-                let state_diff = storage
-                    .load_state_diff(block, Some(new_block))
-                    .map_err(|e| format!("failed to load committed state: {}", e))?;
+                let state_diff = storage.load_state_diff(block, Some(new_block)).map_err(|e| {
+                    MaintainerError::Database(format!("failed to load committed state: {}", e))
+                })?;
 
                 if let Some((_, state_diff)) = state_diff {
                     // Diff exists, update the state and return it.
-                    let mut new_state = state.clone();
+                    let (_, old_state) = self.account_state.take().expect("checked above");
+                    let mut new_state = old_state.clone();
+                    let changed_accounts: Vec<u32> =
+                        state_diff.iter().map(|(account_id, _)| *account_id).collect();
 
                     apply_updates(&mut new_state, state_diff);
+
+                    let account_tree = self
+                        .circuit_account_tree
+                        .as_mut()
+                        .expect("circuit account tree must be initialized along with account state");
+                    for account_id in changed_accounts {
+                        match new_state.get(&account_id) {
+                            Some(account) => {
+                                account_tree.insert(account_id, CircuitAccount::from(account.clone()))
+                            }
+                            None => {
+                                account_tree.remove(account_id);
+                            }
+                        }
+                    }
+
+                    self.push_snapshot(block, old_state);
                     debug!("Prover state is updated ({} => {})", block, new_block);
 
                     self.account_state = Some((new_block, new_state));
                 }
+
+                Ok(())
             }
-            None => {
-                // State is not initialized, load it.
-                let (block, accounts) = storage
-                    .load_committed_state(Some(new_block))
-                    .map_err(|e| format!("failed to load committed state: {}", e))?;
+            None => self.load_account_state(storage, new_block),
+        }
+    }
 
-                debug!("Prover state is initialized");
+    /// Loads committed state for `new_block` from storage and rebuilds the
+    /// cached circuit account tree from scratch. Used both for the initial
+    /// load and as a fallback when a reorg outruns `state_snapshots`.
+    fn load_account_state(
+        &mut self,
+        storage: &storage::StorageProcessor,
+        new_block: u32,
+    ) -> Result<(), MaintainerError> {
+        let (block, accounts) = storage.load_committed_state(Some(new_block)).map_err(|e| {
+            MaintainerError::Database(format!("failed to load committed state: {}", e))
+        })?;
 
-                self.account_state = Some((block, accounts));
-            }
-        };
+        let mut account_tree = CircuitAccountTree::new(models::params::account_tree_depth() as u32);
+        for (&account_id, account) in &accounts {
+            account_tree.insert(account_id, CircuitAccount::from(account.clone()));
+        }
+        self.circuit_account_tree = Some(account_tree);
+        self.account_state = Some((block, accounts));
+        self.state_snapshots.clear();
+        self.write_pool()?.forget_chain_tracking();
+
+        debug!("Prover state is initialized");
 
         Ok(())
     }
 
-    /// Builds an `CircutAccountTree` based on the stored account state.
-    ///
-    /// This method does not update the account state itself and expects
-    /// it to be up to date.
-    fn build_account_tree(&self) -> CircuitAccountTree {
-        assert!(
-            self.account_state.is_some(),
-            "There is no state to build a circuit account tree"
-        );
-
-        let mut account_tree = CircuitAccountTree::new(models::params::account_tree_depth() as u32);
+    /// Pushes the previous `(block, AccountMap)` onto the snapshot stack,
+    /// dropping the oldest entry once `MAX_ACCOUNT_STATE_SNAPSHOTS` is exceeded.
+    fn push_snapshot(&mut self, block: u32, state: AccountMap) {
+        push_account_state_snapshot(&mut self.state_snapshots, block, state);
+    }
 
-        if let Some((_, ref state)) = self.account_state {
-            for (&account_id, account) in state {
-                let circuit_account = CircuitAccount::from(account.clone());
-                account_tree.insert(account_id, circuit_account);
-            }
-        }
+    /// Pops snapshots off the stack, restoring `account_state` to each one
+    /// in turn, until `block` is reached exactly. Rebuilds the cached
+    /// circuit account tree from the restored state once found.
+    ///
+    /// Returns an error (without consuming the whole stack) if no snapshot
+    /// for `block` is present, e.g. because the reorg is deeper than
+    /// `MAX_ACCOUNT_STATE_SNAPSHOTS`.
+    fn revert_to(&mut self, block: u32) -> Result<(), MaintainerError> {
+        revert_account_state(
+            &mut self.state_snapshots,
+            &mut self.account_state,
+            &mut self.circuit_account_tree,
+            block,
+        )?;
+        self.write_pool()?.realign_chain_tracking(block as i64);
+        Ok(())
+    }
 
-        account_tree
+    /// Returns a working copy of the cached circuit account tree for the
+    /// caller to mutate while replaying a block's transactions.
+    ///
+    /// Cloning only duplicates the tree's in-memory representation; unlike
+    /// rebuilding it from `account_state`, no Merkle hashes are recomputed.
+    fn build_account_tree(&self) -> CircuitAccountTree {
+        self.circuit_account_tree
+            .as_ref()
+            .expect("There is no cached circuit account tree to build a block upon")
+            .clone()
     }
 
     fn build_prover_data(
         &mut self,
         storage: &storage::StorageProcessor,
         commit_operation: &models::Operation,
-    ) -> Result<ProverData, String> {
+    ) -> Result<ProverData, MaintainerError> {
         let block_number = commit_operation.block.block_number;
 
         info!("building prover data for block {}", &block_number);
@@ -283,7 +739,9 @@ impl Maintainer {
         let initial_root = witness_accum.account_tree.root_hash();
         let ops = storage
             .get_block_operations(block_number)
-            .map_err(|e| format!("failed to get block operations {}", e))?;
+            .map_err(|e| {
+                MaintainerError::Database(format!("failed to get block operations {}", e))
+            })?;
 
         let mut operations = vec![];
         let mut pub_data = vec![];
@@ -308,7 +766,12 @@ impl Maintainer {
                         .signature
                         .signature
                         .serialize_packed()
-                        .map_err(|e| format!("failed to pack transaction signature {}", e))?;
+                        .map_err(|e| {
+                        MaintainerError::Fatal(format!(
+                            "failed to pack transaction signature {}",
+                            e
+                        ))
+                    })?;
 
                     let (
                         first_sig_msg,
@@ -320,7 +783,10 @@ impl Maintainer {
                         &sig_packed,
                         &transfer.tx.get_bytes(),
                         &transfer.tx.signature.pub_key,
-                    )?;
+                    )
+                    .map_err(|e| {
+                        MaintainerError::Fatal(format!("failed to prepare signature data: {}", e))
+                    })?;
 
                     let transfer_operations = calculate_transfer_operations_from_witness(
                         &transfer_witness,
@@ -347,7 +813,12 @@ impl Maintainer {
                         .signature
                         .signature
                         .serialize_packed()
-                        .map_err(|e| format!("failed to pack transaction signature {}", e))?;
+                        .map_err(|e| {
+                        MaintainerError::Fatal(format!(
+                            "failed to pack transaction signature {}",
+                            e
+                        ))
+                    })?;
 
                     let (
                         first_sig_msg,
@@ -359,7 +830,10 @@ impl Maintainer {
                         &sig_packed,
                         &transfer_to_new.tx.get_bytes(),
                         &transfer_to_new.tx.signature.pub_key,
-                    )?;
+                    )
+                    .map_err(|e| {
+                        MaintainerError::Fatal(format!("failed to prepare signature data: {}", e))
+                    })?;
 
                     let transfer_to_new_operations =
                         calculate_transfer_to_new_operations_from_witness(
@@ -387,7 +861,12 @@ impl Maintainer {
                         .signature
                         .signature
                         .serialize_packed()
-                        .map_err(|e| format!("failed to pack transaction signature {}", e))?;
+                        .map_err(|e| {
+                        MaintainerError::Fatal(format!(
+                            "failed to pack transaction signature {}",
+                            e
+                        ))
+                    })?;
 
                     let (
                         first_sig_msg,
@@ -399,7 +878,10 @@ impl Maintainer {
                         &sig_packed,
                         &withdraw.tx.get_bytes(),
                         &withdraw.tx.signature.pub_key,
-                    )?;
+                    )
+                    .map_err(|e| {
+                        MaintainerError::Fatal(format!("failed to prepare signature data: {}", e))
+                    })?;
 
                     let withdraw_operations = calculate_withdraw_operations_from_witness(
                         &withdraw_witness,
@@ -426,7 +908,9 @@ impl Maintainer {
                         .signature
                         .signature
                         .serialize_packed()
-                        .map_err(|e| format!("failed to pack signature: {}", e))?;
+                        .map_err(|e| {
+                        MaintainerError::Fatal(format!("failed to pack signature: {}", e))
+                    })?;
 
                     let (
                         first_sig_msg,
@@ -438,7 +922,10 @@ impl Maintainer {
                         &sig_packed,
                         &close.tx.get_bytes(),
                         &close.tx.signature.pub_key,
-                    )?;
+                    )
+                    .map_err(|e| {
+                        MaintainerError::Fatal(format!("failed to prepare signature data: {}", e))
+                    })?;
 
                     let close_account_operations = calculate_close_account_operations_from_witness(
                         &close_account_witness,
@@ -482,22 +969,34 @@ impl Maintainer {
 
         witness_accum.add_operation_with_pubdata(operations, pub_data);
         witness_accum.extend_pubdata_with_noops();
-        assert_eq!(
-            witness_accum.pubdata.len(),
-            64 * models::params::block_size_chunks()
-        );
-        assert_eq!(
-            witness_accum.operations.len(),
-            models::params::block_size_chunks()
-        );
+        if witness_accum.pubdata.len() != 64 * models::params::block_size_chunks() {
+            return Err(MaintainerError::Fatal(format!(
+                "witness pubdata for block {} has length {}, expected {}",
+                block_number,
+                witness_accum.pubdata.len(),
+                64 * models::params::block_size_chunks()
+            )));
+        }
+        if witness_accum.operations.len() != models::params::block_size_chunks() {
+            return Err(MaintainerError::Fatal(format!(
+                "witness operations for block {} has length {}, expected {}",
+                block_number,
+                witness_accum.operations.len(),
+                models::params::block_size_chunks()
+            )));
+        }
 
         witness_accum.collect_fees(&fees);
-        assert_eq!(
-            witness_accum
-                .root_after_fees
-                .expect("root_after_fees not present"),
-            commit_operation.block.new_root_hash
-        );
+        let root_after_fees = witness_accum
+            .root_after_fees
+            .ok_or_else(|| MaintainerError::Fatal("root_after_fees not present".to_owned()))?;
+        if root_after_fees != commit_operation.block.new_root_hash {
+            return Err(MaintainerError::Fatal(format!(
+                "computed root after fees for block {} does not match the committed root \
+                 (computed: {:?}, committed: {:?})",
+                block_number, root_after_fees, commit_operation.block.new_root_hash
+            )));
+        }
         witness_accum.calculate_pubdata_commitment();
 
         Ok(ProverData {
@@ -512,4 +1011,129 @@ impl Maintainer {
             validator_account: witness_accum.fee_account_witness.unwrap(),
         })
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_root_chain_accepts_no_prior_root() {
+        let old_root = Fr::from_str("1").unwrap();
+        assert!(check_root_chain(None, &old_root, 1).is_ok());
+    }
+
+    #[test]
+    fn check_root_chain_accepts_matching_root() {
+        let root = Fr::from_str("1").unwrap();
+        assert!(check_root_chain(Some(&root), &root, 2).is_ok());
+    }
+
+    #[test]
+    fn check_root_chain_rejects_mismatched_root() {
+        let expected = Fr::from_str("1").unwrap();
+        let old_root = Fr::from_str("2").unwrap();
+        match check_root_chain(Some(&expected), &old_root, 3) {
+            Err(MaintainerError::Fatal(_)) => {}
+            other => panic!("expected MaintainerError::Fatal, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn compress_decompress_round_trip() {
+        let original = b"some prover data bytes, repeated repeated repeated".to_vec();
+        let compressed = compress(&original, 3).expect("compression failed");
+        let decompressed = decompress(&compressed).expect("decompression failed");
+        assert_eq!(decompressed, original);
+    }
+
+    #[test]
+    fn verify_chain_accepts_unbroken_chain() {
+        let mut pool = ProversDataPool::new(None);
+        let root_a = Fr::from_str("1").unwrap();
+        let root_b = Fr::from_str("2").unwrap();
+        let root_c = Fr::from_str("3").unwrap();
+        pool.roots.insert(1, (root_a.clone(), root_b.clone()));
+        pool.roots.insert(2, (root_b, root_c));
+
+        assert!(pool.verify_chain(1, 2).is_ok());
+    }
+
+    #[test]
+    fn verify_chain_rejects_broken_chain() {
+        let mut pool = ProversDataPool::new(None);
+        let root_a = Fr::from_str("1").unwrap();
+        let root_b = Fr::from_str("2").unwrap();
+        let root_other = Fr::from_str("99").unwrap();
+        let root_c = Fr::from_str("3").unwrap();
+        pool.roots.insert(1, (root_a, root_b));
+        pool.roots.insert(2, (root_other, root_c));
+
+        match pool.verify_chain(1, 2) {
+            Err(MaintainerError::Fatal(_)) => {}
+            other => panic!("expected MaintainerError::Fatal, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn verify_chain_rejects_missing_block() {
+        let mut pool = ProversDataPool::new(None);
+        let root_a = Fr::from_str("1").unwrap();
+        let root_b = Fr::from_str("2").unwrap();
+        pool.roots.insert(1, (root_a, root_b));
+
+        match pool.verify_chain(1, 2) {
+            Err(MaintainerError::Inconsistent(_)) => {}
+            other => panic!("expected MaintainerError::Inconsistent, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn push_account_state_snapshot_evicts_oldest_beyond_bound() {
+        let mut history = VecDeque::new();
+        for block in 0..(MAX_ACCOUNT_STATE_SNAPSHOTS as u32 + 5) {
+            push_account_state_snapshot(&mut history, block, AccountMap::default());
+        }
+
+        assert_eq!(history.len(), MAX_ACCOUNT_STATE_SNAPSHOTS);
+        assert_eq!(history.front().unwrap().0, 5);
+        assert_eq!(history.back().unwrap().0, MAX_ACCOUNT_STATE_SNAPSHOTS as u32 + 4);
+    }
+
+    #[test]
+    fn revert_account_state_restores_matching_snapshot() {
+        let mut history = VecDeque::new();
+        push_account_state_snapshot(&mut history, 1, AccountMap::default());
+        push_account_state_snapshot(&mut history, 2, AccountMap::default());
+        push_account_state_snapshot(&mut history, 3, AccountMap::default());
+
+        let mut account_state = None;
+        let mut circuit_account_tree = None;
+
+        revert_account_state(&mut history, &mut account_state, &mut circuit_account_tree, 2)
+            .expect("revert to an existing snapshot should succeed");
+
+        assert_eq!(account_state.unwrap().0, 2);
+        assert!(circuit_account_tree.is_some());
+        // The snapshot for block 3 (and the matched snapshot for block 2)
+        // were popped off; only block 1 remains.
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.back().unwrap().0, 1);
+    }
+
+    #[test]
+    fn revert_account_state_rejects_missing_snapshot() {
+        let mut history = VecDeque::new();
+        push_account_state_snapshot(&mut history, 1, AccountMap::default());
+
+        let mut account_state: Option<(u32, AccountMap)> = None;
+        let mut circuit_account_tree = None;
+
+        let result =
+            revert_account_state(&mut history, &mut account_state, &mut circuit_account_tree, 42);
+
+        assert!(matches!(result, Err(MaintainerError::Inconsistent(_))));
+        // No block matched, so the tree is never (re)built from a snapshot.
+        assert!(circuit_account_tree.is_none());
+    }
 }
\ No newline at end of file